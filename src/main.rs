@@ -1,8 +1,76 @@
 mod qrcode;
 mod totp;
 
-fn main() {
-    let url = qrcode::extract_totp_uri(String::from("/Users/p0n002h/tmp/test.png")).unwrap();
-    let spec = totp::TOTPSpec::new(url);
+use std::env;
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+use url::Url;
+
+/// otp [OPTIONS] [IMAGE]
+///
+/// Decode an otpauth URI from a QR code image and print the current
+/// token. The URI can also be supplied directly, skipping decoding.
+fn usage() -> String {
+    String::from(
+        "usage: otp [--uri <otpauth://...> | --secret <otpauth://...>] [--watch] [IMAGE]",
+    )
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+
+    let mut uri: Option<String> = None;
+    let mut image: Option<String> = None;
+    let mut watch = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // `--secret` is an alias for `--uri`; both take a full
+            // otpauth URI and bypass the QR decoder.
+            "--uri" | "--secret" => {
+                uri = Some(
+                    args.next()
+                        .ok_or_else(|| format!("{} requires a value\n{}", arg, usage()))?,
+                );
+            }
+            "--watch" => watch = true,
+            "-h" | "--help" => {
+                println!("{}", usage());
+                return Ok(());
+            }
+            other => {
+                if image.is_some() {
+                    return Err(format!("unexpected argument: {}\n{}", other, usage()).into());
+                }
+                image = Some(other.to_owned());
+            }
+        }
+    }
+
+    // Resolve the otpauth URI either from the flag or by decoding the
+    // QR code in the supplied image.
+    let url = match uri {
+        Some(u) => Url::parse(&u)?,
+        None => {
+            let path = image.ok_or_else(|| format!("no image path given\n{}", usage()))?;
+            qrcode::extract_totp_uri(path).ok_or("failed to extract otpauth uri from image")?
+        }
+    };
+
+    let spec = totp::TOTPSpec::new(url)?;
+
+    if watch {
+        // Re-print the token every period, showing how long the
+        // current one stays valid.
+        loop {
+            let remaining = spec.seconds_remaining();
+            println!("{} ({}s remaining)", spec.get_otp(), remaining);
+            thread::sleep(Duration::from_secs(remaining.max(1)));
+        }
+    }
+
     println!("{}", spec.get_otp());
+    Ok(())
 }
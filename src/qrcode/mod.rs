@@ -1,5 +1,7 @@
 use bardecoder;
 use image;
+use image::Luma;
+use qrcode::QrCode;
 use url::Url;
 
 /// extract_totp_uri extracts the uri identifier from the QRcode
@@ -35,6 +37,30 @@ pub fn extract_totp_uri(file_path: String) -> Option<Url> {
     return Some(parsed_url);
 }
 
+/// encode_totp_uri renders an otpauth URI into a QR code and writes it
+/// to the given path. The image format follows the path's extension, so
+/// a `.png` path produces a PNG. This is the inverse of
+/// extract_totp_uri and lets users provision a new authenticator entry
+/// from a secret they own.
+pub fn encode_totp_uri(uri: &str, file_path: String) -> Option<()> {
+    let code = match QrCode::new(uri.as_bytes()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to build QR code: {}", e);
+            return None;
+        }
+    };
+
+    let img = code.render::<Luma<u8>>().build();
+    match img.save(file_path) {
+        Ok(_) => Some(()),
+        Err(e) => {
+            eprintln!("Failed to save file: {}", e);
+            None
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -47,6 +73,19 @@ mod tests {
         assert_eq!(u, s);
     }
 
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let uri = "otpauth://totp/otplib-website:otplib-demo-user?secret=H4ZWJCQZEREL2IE2&period=30&digits=6&algorithm=SHA1&issuer=otplib-website";
+        let mut path = std::env::temp_dir();
+        path.push("otp_round_trip.png");
+        let path = path.to_str().unwrap().to_string();
+
+        assert!(encode_totp_uri(uri, path.clone()).is_some());
+
+        let decoded = extract_totp_uri(path).unwrap();
+        assert_eq!(decoded, Url::parse(uri).unwrap());
+    }
+
     #[test]
     fn test_totp_parse_empty() {
         let f = String::from("./testdata/emtpy.png");
@@ -5,19 +5,65 @@ extern crate url;
 use data_encoding::BASE32;
 use ring::hmac;
 use std::borrow::Cow::Borrowed;
-use std::process;
+use std::error;
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
 
 use std::convert::From;
 
+/// Errors that can occur while parsing an otpauth URI into a TOTPSpec.
+#[derive(Debug)]
+pub enum OtpError {
+    /// The URI carried no `secret`, or an empty one.
+    MissingSecret,
+    /// The `secret` was not valid base32.
+    InvalidSecret(String),
+    /// A numeric field (`period`, `counter`, `digits`) was not numeric.
+    NonNumericField(String),
+    /// The URI host was neither `totp` nor `hotp`.
+    UnsupportedHost(String),
+    /// The `algorithm` was not one of SHA1, SHA256 or SHA512.
+    UnsupportedAlgorithm(String),
+}
+
+impl fmt::Display for OtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OtpError::MissingSecret => write!(f, "missing or empty secret"),
+            OtpError::InvalidSecret(s) => write!(f, "invalid base32 secret: {}", s),
+            OtpError::NonNumericField(field) => write!(f, "non-numeric {} field", field),
+            OtpError::UnsupportedHost(host) => write!(f, "unsupported scheme/host: {}", host),
+            OtpError::UnsupportedAlgorithm(a) => write!(f, "unsupported HMAC algorithm: {}", a),
+        }
+    }
+}
+
+impl error::Error for OtpError {}
+
+/// The moving factor of the OTP. TOTP derives the counter from the
+/// wall-clock time and a step `period`, while HOTP uses an explicit
+/// `counter` value carried in the URI.
+#[derive(Debug, Clone, Copy)]
+pub enum OtpKind {
+    Totp { period: u32 },
+    Hotp { counter: u64 },
+}
+
+impl Default for OtpKind {
+    fn default() -> Self {
+        OtpKind::Totp { period: 30 }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct TOTPSpec {
     secret: Vec<u8>,
-    period: u32,
+    kind: OtpKind,
     digits: u8,
     algorithm: String,
     issuer: String,
+    label: String,
 }
 
 struct Bytes([u8; 8]);
@@ -26,22 +72,31 @@ impl TOTPSpec {
     pub fn default() -> TOTPSpec {
         TOTPSpec {
             secret: Vec::new(),
-            period: 30,
+            kind: OtpKind::default(),
             digits: 6,
             algorithm: String::from("SHA1"),
             issuer: String::from(""),
+            label: String::from(""),
         }
     }
 
-    /// Get a new TOTPSpec object based on a url.Url object.
-    pub fn new(u: Url) -> Self {
-        if u.host_str() != Some("totp") {
-            eprintln!("Unsupported URL format");
-            process::exit(1);
-        }
+    /// Get a new TOTPSpec object based on a url.Url object. The host
+    /// selects the mode: `totp` reads an optional `period`, `hotp`
+    /// reads `counter` as its moving factor (0 when the URI has no
+    /// `counter`). A malformed
+    /// secret, non-numeric field or unknown host is reported as an
+    /// `OtpError` rather than crashing the program.
+    pub fn new(u: Url) -> Result<TOTPSpec, OtpError> {
+        let host = match u.host_str() {
+            Some("totp") | Some("hotp") => u.host_str().unwrap().to_owned(),
+            other => return Err(OtpError::UnsupportedHost(other.unwrap_or("").to_owned())),
+        };
 
         let query_string = u.query_pairs();
         let mut spec = TOTPSpec::default();
+        let mut period: u32 = 30;
+        let mut counter: u64 = 0;
+        let mut has_secret = false;
 
         // Sample url
         // otpauth://totp/otplib-website:otplib-demo-user?
@@ -53,19 +108,34 @@ impl TOTPSpec {
                     let mut s = x.into_owned();
                     pad_string_to_base32(&mut s);
 
-                    spec.secret = BASE32.decode(s.as_bytes()).unwrap();
+                    spec.secret = BASE32
+                        .decode(s.as_bytes())
+                        .map_err(|_| OtpError::InvalidSecret(s.clone()))?;
+                    has_secret = true;
                 }
                 (Borrowed("period"), x) => {
-                    spec.period = x.into_owned().parse().unwrap();
-                    if spec.period < 1 {
-                        spec.period = 30
+                    period = x
+                        .into_owned()
+                        .parse()
+                        .map_err(|_| OtpError::NonNumericField(String::from("period")))?;
+                    if period < 1 {
+                        period = 30
                     }
                 }
+                (Borrowed("counter"), x) => {
+                    counter = x
+                        .into_owned()
+                        .parse()
+                        .map_err(|_| OtpError::NonNumericField(String::from("counter")))?;
+                }
                 (Borrowed("algorithm"), x) => {
                     spec.algorithm = x.into_owned();
                 }
                 (Borrowed("digits"), x) => {
-                    spec.digits = x.into_owned().parse().unwrap();
+                    spec.digits = x
+                        .into_owned()
+                        .parse()
+                        .map_err(|_| OtpError::NonNumericField(String::from("digits")))?;
                     if spec.digits < 6 || spec.digits > 8 {
                         // default to 6 for any invalid or unsupported
                         // digit count.
@@ -79,7 +149,36 @@ impl TOTPSpec {
             }
         }
 
-        spec
+        // The path carries the account label, optionally prefixed by the
+        // issuer as `issuer:label`. The `issuer` query parameter, when
+        // present, takes precedence over the path prefix.
+        let path = percent_decode(u.path().trim_start_matches('/'));
+        if !path.is_empty() {
+            match path.split_once(':') {
+                Some((issuer, label)) => {
+                    spec.label = label.trim().to_owned();
+                    if spec.issuer.is_empty() {
+                        spec.issuer = issuer.trim().to_owned();
+                    }
+                }
+                None => spec.label = path.trim().to_owned(),
+            }
+        }
+
+        if !has_secret || spec.secret.is_empty() {
+            return Err(OtpError::MissingSecret);
+        }
+
+        if hmac_algorithm(&spec.algorithm).is_none() {
+            return Err(OtpError::UnsupportedAlgorithm(spec.algorithm.clone()));
+        }
+
+        spec.kind = match host.as_str() {
+            "hotp" => OtpKind::Hotp { counter },
+            _ => OtpKind::Totp { period },
+        };
+
+        Ok(spec)
     }
 
     /// Utility method that reads the state of the spec and generates the
@@ -87,13 +186,99 @@ impl TOTPSpec {
     pub fn get_otp(&self) -> String {
         get_otp(&self, get_counter_as_bytes(&self))
     }
+
+    /// Generate the token for an arbitrary moving factor, ignoring the
+    /// spec's own counter. Useful for HOTP resynchronisation and for
+    /// checking a window of TOTP steps.
+    pub fn get_otp_at(&self, counter: u64) -> String {
+        get_otp(&self, counter)
+    }
+
+    /// Serialize the spec back into a canonical otpauth URI. The raw
+    /// secret is base32-encoded with the `=` padding stripped, matching
+    /// the form emitted by common authenticator apps.
+    pub fn to_uri(&self) -> String {
+        let secret = BASE32.encode(&self.secret);
+        let secret = secret.trim_end_matches('=');
+
+        // The path is `issuer:label` (or just `label` when no issuer is
+        // set). The label, path issuer and query issuer are all
+        // percent-encoded so values containing a space, `:` or `&` still
+        // produce a well-formed URI.
+        let issuer = percent_encode(&self.issuer);
+        let path = if self.issuer.is_empty() {
+            percent_encode(&self.label)
+        } else {
+            format!("{}:{}", issuer, percent_encode(&self.label))
+        };
+
+        match self.kind {
+            OtpKind::Totp { period } => format!(
+                "otpauth://totp/{path}?secret={secret}&period={period}\
+                 &digits={digits}&algorithm={algorithm}&issuer={issuer}",
+                path = path,
+                issuer = issuer,
+                secret = secret,
+                period = period,
+                digits = self.digits,
+                algorithm = self.algorithm,
+            ),
+            OtpKind::Hotp { counter } => format!(
+                "otpauth://hotp/{path}?secret={secret}&counter={counter}\
+                 &digits={digits}&algorithm={algorithm}&issuer={issuer}",
+                path = path,
+                issuer = issuer,
+                secret = secret,
+                counter = counter,
+                digits = self.digits,
+                algorithm = self.algorithm,
+            ),
+        }
+    }
+
+    /// Number of seconds left in the current TOTP step. Returns 0 for
+    /// HOTP, whose counter does not advance with time.
+    pub fn seconds_remaining(&self) -> u64 {
+        match self.kind {
+            OtpKind::Totp { period } => {
+                let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                period as u64 - (t.as_secs() % period as u64)
+            }
+            OtpKind::Hotp { .. } => 0,
+        }
+    }
+
+    /// Check a user-supplied code against the tokens for the current
+    /// counter and the `skew` steps on either side of it, so small
+    /// clock drift between client and server is tolerated. The
+    /// comparison is constant-time to avoid leaking how many leading
+    /// digits matched. Returns true on the first match.
+    pub fn verify(&self, candidate: &str, skew: u32) -> bool {
+        let base = get_counter_as_bytes(self);
+        let low = base.saturating_sub(skew as u64);
+        let high = base.saturating_add(skew as u64);
+
+        for counter in low..=high {
+            let token = get_otp(self, counter);
+            if ring::constant_time::verify_slices_are_equal(
+                candidate.as_bytes(),
+                token.as_bytes(),
+            )
+            .is_ok()
+            {
+                return true;
+            }
+        }
+
+        false
+    }
 }
 
-/// Implement the from trait to convert a u32 into a big endian
+/// Implement the from trait to convert a u64 into a big endian
 /// representation where the bytearray's lower index will have the
 /// highest significant value. Eg: 1 -> [0, 0, 0, 0, 0, 0, 0, 1]
-impl From<u32> for Bytes {
-    fn from(v: u32) -> Bytes {
+impl From<u64> for Bytes {
+    fn from(v: u64) -> Bytes {
         let mut c = v;
         let mut x = [0u8; 8];
         for i in 0..8 {
@@ -104,28 +289,55 @@ impl From<u32> for Bytes {
     }
 }
 
-/// Get the counter value at the current time as the interval number
-/// which will be used to calculate the hash for the HOTP.
-fn get_counter_as_bytes(spec: &TOTPSpec) -> u32 {
-    let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    t.as_secs() as u32 / spec.period
+/// Get the moving factor for the spec. For TOTP this is the number of
+/// `period`-sized intervals since the epoch; for HOTP it is the fixed
+/// counter carried in the URI.
+fn get_counter_as_bytes(spec: &TOTPSpec) -> u64 {
+    match spec.kind {
+        OtpKind::Totp { period } => {
+            let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            t.as_secs() / period as u64
+        }
+        OtpKind::Hotp { counter } => counter,
+    }
+}
+
+/// Map an algorithm name to the corresponding ring HMAC primitive, or
+/// `None` for an unknown string. `TOTPSpec::new` uses this to reject an
+/// unsupported `algorithm` up front so token generation never has to
+/// fall back silently or crash.
+fn hmac_algorithm(name: &str) -> Option<hmac::Algorithm> {
+    match name {
+        "SHA1" => Some(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY),
+        "SHA256" => Some(hmac::HMAC_SHA256),
+        "SHA512" => Some(hmac::HMAC_SHA512),
+        _ => None,
+    }
 }
 
 /// Get the OTP for the based on spec.
-pub fn get_otp(spec: &TOTPSpec, counter: u32) -> String {
-    // At the moment, only SHA1 is supported.
-    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, spec.secret.as_slice());
+pub fn get_otp(spec: &TOTPSpec, counter: u64) -> String {
+    // `new()` validates the algorithm and the inherent
+    // `TOTPSpec::default()` sets SHA1, so specs built by either name a
+    // supported primitive. The derived `Default` trait impl leaves the
+    // algorithm empty and panics here.
+    let algorithm = match hmac_algorithm(&spec.algorithm) {
+        Some(a) => a,
+        None => unreachable!("unvalidated algorithm: {}", spec.algorithm),
+    };
+    let key = hmac::Key::new(algorithm, spec.secret.as_slice());
     let tag = hmac::sign(&key, &Bytes::from(counter).0);
+    let tag = tag.as_ref();
 
-    // The offset as the 4 bits from the low-order bits. For example
-    // if the output of the signature is 160 bits, we use the last
-    // 4 bits.
-    let offset: usize = (tag.as_ref()[19] & 0x0f) as usize;
+    // The offset as the 4 bits from the low-order bits of the *last*
+    // byte of the tag. Reading the final byte instead of a fixed index
+    // keeps the truncation correct for 20-, 32- and 64-byte digests.
+    let offset: usize = (tag[tag.len() - 1] & 0x0f) as usize;
 
-    let mut h: u32 = ((tag.as_ref()[offset] & 0x7f) as u32) << 24;
-    h = h | ((tag.as_ref()[offset + 1] & 0xff) as u32) << 16;
-    h = h | ((tag.as_ref()[offset + 2] & 0xff) as u32) << 8;
-    h = h | ((tag.as_ref()[offset + 3] & 0xff) as u32);
+    let mut h: u32 = ((tag[offset] & 0x7f) as u32) << 24;
+    h = h | ((tag[offset + 1] & 0xff) as u32) << 16;
+    h = h | ((tag[offset + 2] & 0xff) as u32) << 8;
+    h = h | ((tag[offset + 3] & 0xff) as u32);
 
     // Format the otp with left padding if the modulo is less than
     // the required digits.
@@ -136,6 +348,43 @@ pub fn get_otp(spec: &TOTPSpec, counter: u32) -> String {
     )
 }
 
+/// Percent-encode a URI component, leaving only the unreserved
+/// characters (RFC 3986 `ALPHA / DIGIT / - . _ ~`) untouched. Used for
+/// the label and issuer so an issuer with a space, `:` or `&` does not
+/// corrupt the emitted URI.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Reverse of `percent_encode` for reading the label back out of a URI
+/// path. Invalid or truncated `%` escapes are left as-is.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(v) = u8::from_str_radix(&s[i + 1..i + 3], 16) {
+                out.push(v);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 /// Pad the secret to have the length divisible by 8 for it to be
 /// decoded as base32.
 fn pad_string_to_base32(s: &mut String) {
@@ -173,10 +422,32 @@ mod tests {
         assert_eq!(x, "520489");
     }
 
+    #[test]
+    fn rfc6238_sha256_counter_1() {
+        let mut spec = TOTPSpec::default();
+        spec.secret = "12345678901234567890123456789012".as_bytes().to_vec();
+        spec.algorithm = String::from("SHA256");
+        spec.digits = 8;
+        let x = get_otp(&spec, 1);
+        assert_eq!(x, "46119246");
+    }
+
+    #[test]
+    fn rfc6238_sha512_counter_1() {
+        let mut spec = TOTPSpec::default();
+        spec.secret = "1234567890123456789012345678901234567890123456789012345678901234"
+            .as_bytes()
+            .to_vec();
+        spec.algorithm = String::from("SHA512");
+        spec.digits = 8;
+        let x = get_otp(&spec, 1);
+        assert_eq!(x, "90693936");
+    }
+
     #[test]
     fn totp_test_with_url() {
         let u = Url::parse("otpauth://totp/test:user?secret=JBSWY3DPEHPK3PXP").unwrap();
-        let spec = TOTPSpec::new(u);
+        let spec = TOTPSpec::new(u).unwrap();
         assert_eq!(get_otp(&spec, 53273637), "927328");
     }
 
@@ -188,6 +459,15 @@ mod tests {
         assert_eq!(Bytes::from(1337).0, v);
     }
 
+    #[test]
+    fn counter_above_u32_not_truncated() {
+        // A counter beyond u32::MAX must survive into the 8-byte wire
+        // form rather than wrapping to its low 32 bits.
+        let big = u32::MAX as u64 + 1;
+        assert_eq!(Bytes::from(big).0, [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_ne!(Bytes::from(big).0, Bytes::from(0u64).0);
+    }
+
     #[test]
     fn pad_bytes_test() {
         let mut s = String::from("totp");
@@ -195,19 +475,115 @@ mod tests {
         assert_eq!(String::from("totp===="), s);
     }
 
+    #[test]
+    fn verify_accepts_skew_window() {
+        // HOTP with a fixed counter gives a deterministic window.
+        let u =
+            Url::parse("otpauth://hotp/test:user?secret=JBSWY3DPEHPK3PXP&counter=53273638")
+                .unwrap();
+        let spec = TOTPSpec::new(u).unwrap();
+
+        // The previous step's token (counter 53273637) is accepted with
+        // skew=1 but rejected with skew=0.
+        let prev = get_otp(&spec, 53273637);
+        assert_eq!(spec.verify(&prev, 1), true);
+        assert_eq!(spec.verify(&prev, 0), false);
+        assert_eq!(spec.verify("000000", 1), false);
+    }
+
     #[test]
     fn totp_spec_default() {
         let spec = TOTPSpec::default();
         assert_eq!(spec.digits, 6);
-        assert_eq!(spec.period, 30);
+        assert!(matches!(spec.kind, OtpKind::Totp { period: 30 }));
         assert_eq!(spec.algorithm, "SHA1");
     }
 
     #[test]
     fn totp_spec_parse() {
         let u = Url::parse("otpauth://totp/test:user?digits=3&secret=JBSWY3DPEHPK3PXP").unwrap();
-        let spec = TOTPSpec::new(u);
+        let spec = TOTPSpec::new(u).unwrap();
         assert_eq!(spec.digits, 6);
-        assert_eq!(spec.period, 30);
+        assert!(matches!(spec.kind, OtpKind::Totp { period: 30 }));
+    }
+
+    #[test]
+    fn to_uri_round_trips() {
+        let original =
+            "otpauth://totp/test:user?secret=JBSWY3DPEHPK3PXP&period=30&digits=6&algorithm=SHA1";
+        let spec = TOTPSpec::new(Url::parse(original).unwrap()).unwrap();
+        let rebuilt = TOTPSpec::new(Url::parse(&spec.to_uri()).unwrap()).unwrap();
+
+        assert_eq!(rebuilt.secret, spec.secret);
+        assert_eq!(rebuilt.digits, spec.digits);
+        assert_eq!(rebuilt.issuer, spec.issuer);
+        assert_eq!(rebuilt.label, spec.label);
+        assert_eq!(spec.label, "user");
+        assert_eq!(spec.get_otp_at(53273637), rebuilt.get_otp_at(53273637));
+    }
+
+    #[test]
+    fn to_uri_percent_encodes_issuer_and_label() {
+        let mut spec = TOTPSpec::default();
+        spec.secret = BASE32.decode(b"JBSWY3DPEHPK3PXP").unwrap();
+        spec.issuer = String::from("Big Corp & Co");
+        spec.label = String::from("alice@example.com");
+
+        let uri = spec.to_uri();
+        // No raw space/`&` leaks into the URI, and it still parses.
+        assert!(!uri.contains(' '));
+        let rebuilt = TOTPSpec::new(Url::parse(&uri).unwrap()).unwrap();
+        assert_eq!(rebuilt.issuer, spec.issuer);
+        assert_eq!(rebuilt.label, spec.label);
+    }
+
+    #[test]
+    fn hotp_spec_parse() {
+        let u =
+            Url::parse("otpauth://hotp/test:user?secret=JBSWY3DPEHPK3PXP&counter=9").unwrap();
+        let spec = TOTPSpec::new(u).unwrap();
+        assert!(matches!(spec.kind, OtpKind::Hotp { counter: 9 }));
+        // The explicit counter drives the token regardless of time.
+        assert_eq!(spec.get_otp(), get_otp(&spec, 9));
+    }
+
+    #[test]
+    fn new_rejects_missing_secret() {
+        let u = Url::parse("otpauth://totp/test:user?period=30").unwrap();
+        assert!(matches!(TOTPSpec::new(u), Err(OtpError::MissingSecret)));
+    }
+
+    #[test]
+    fn new_rejects_invalid_secret() {
+        let u = Url::parse("otpauth://totp/test:user?secret=10").unwrap();
+        assert!(matches!(TOTPSpec::new(u), Err(OtpError::InvalidSecret(_))));
+    }
+
+    #[test]
+    fn new_rejects_non_numeric_period() {
+        let u = Url::parse("otpauth://totp/test:user?secret=JBSWY3DPEHPK3PXP&period=abc").unwrap();
+        assert!(matches!(
+            TOTPSpec::new(u),
+            Err(OtpError::NonNumericField(_))
+        ));
+    }
+
+    #[test]
+    fn new_rejects_unsupported_algorithm() {
+        let u = Url::parse("otpauth://totp/test:user?secret=JBSWY3DPEHPK3PXP&algorithm=SHA3")
+            .unwrap();
+        assert!(matches!(
+            TOTPSpec::new(u),
+            Err(OtpError::UnsupportedAlgorithm(_))
+        ));
+    }
+
+    #[test]
+    fn new_rejects_unsupported_host() {
+        let u = Url::parse("otpauth://motp/test:user?secret=JBSWY3DPEHPK3PXP").unwrap();
+        assert!(matches!(
+            TOTPSpec::new(u),
+            Err(OtpError::UnsupportedHost(_))
+        ));
     }
 }